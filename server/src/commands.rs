@@ -0,0 +1,218 @@
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A command sent by a client over its `LinesCodec` connection.
+///
+/// Each variant corresponds to one verb in the wire protocol. `parse`
+/// turns a raw line into a `ClientCommand`, returning a human-readable
+/// error string (suitable for wrapping in `ServerMessage::Error`) when
+/// the line doesn't match any known shape. Also (de)serializable so a
+/// `federation::Message::RelayCommand` can carry one between servers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ClientCommand {
+    Join {
+        room: String,
+        name: String,
+        spectator: bool,
+        /// Set when the room is joined as `room@peer-addr`, meaning the
+        /// opponent lives on the peer server at that address rather than
+        /// locally. See `RoomState::remote` in `main.rs`.
+        remote: Option<SocketAddr>,
+    },
+    Attack { power: i32 },
+    Defend,
+    Say { text: String },
+    Quit,
+}
+
+/// A snapshot of both players in a room, handed to spectators on join
+/// and carried in a `federation::Message::RelayState` so the originating
+/// server can mirror a remote opponent's hp back to its local player.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub players: Vec<PlayerSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerSnapshot {
+    pub name: String,
+    pub hp: i32,
+}
+
+/// A message sent by the server down to a single client.
+///
+/// `render` produces the line that gets written to the `Framed` sink;
+/// the format is `verb key=value key=value ...`, kept simple and
+/// greppable rather than going through `serde_json` for every line.
+/// Two variants differ: `Error` carries its reason as free text after
+/// the verb, and `Snapshot` carries its room as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    YourTurn,
+    Damaged { by: Uuid, amount: i32, hp: i32 },
+    RoomFull,
+    Error { reason: String },
+    GameOver { winner: Uuid },
+    ServerShutdown,
+    Snapshot { room: RoomSnapshot },
+}
+
+impl ClientCommand {
+    /// Parses a single line of client input into a `ClientCommand`.
+    ///
+    /// Returns `Err(reason)` on anything that isn't a recognized verb
+    /// or is missing a required argument, rather than panicking.
+    pub fn parse(line: &str) -> Result<ClientCommand, String> {
+        let mut parts = line.trim().splitn(2, ' ');
+        let verb = parts.next().unwrap_or("").to_lowercase();
+        let rest = parts.next().unwrap_or("").trim();
+
+        match verb.as_str() {
+            "join" => {
+                let mut args = rest.splitn(3, ' ');
+                let room_token = args.next().unwrap_or("").trim();
+                let name = args.next().unwrap_or("").trim();
+                let mode = args.next().unwrap_or("").trim();
+                if room_token.is_empty() || name.is_empty() {
+                    return Err("join requires a room and a name".into());
+                }
+                let (room, remote) = match room_token.split_once('@') {
+                    Some((room, addr)) => {
+                        let addr = addr
+                            .parse::<SocketAddr>()
+                            .map_err(|_| format!("invalid peer address {:?}", addr))?;
+                        (room, Some(addr))
+                    }
+                    None => (room_token, None),
+                };
+                Ok(ClientCommand::Join {
+                    room: room.to_string(),
+                    name: name.to_string(),
+                    spectator: mode.eq_ignore_ascii_case("spectate"),
+                    remote,
+                })
+            }
+            "attack" => {
+                let power = rest
+                    .parse::<i32>()
+                    .map_err(|_| "attack requires a numeric power".to_string())?;
+                Ok(ClientCommand::Attack { power })
+            }
+            "defend" => Ok(ClientCommand::Defend),
+            "say" => {
+                if rest.is_empty() {
+                    return Err("say requires a message".into());
+                }
+                Ok(ClientCommand::Say {
+                    text: rest.to_string(),
+                })
+            }
+            "quit" => Ok(ClientCommand::Quit),
+            other => Err(format!("unknown command {:?}", other)),
+        }
+    }
+}
+
+impl ServerMessage {
+    /// Renders a `ServerMessage` as the line written to the client's sink.
+    pub fn render(&self) -> String {
+        match self {
+            ServerMessage::YourTurn => "your_turn".to_string(),
+            ServerMessage::Damaged { by, amount, hp } => {
+                format!("damaged by={} amount={} hp={}", by, amount, hp)
+            }
+            ServerMessage::RoomFull => "room_full".to_string(),
+            ServerMessage::Error { reason } => format!("error {}", reason),
+            ServerMessage::GameOver { winner } => format!("game_over winner={}", winner),
+            ServerMessage::ServerShutdown => "server_shutdown".to_string(),
+            ServerMessage::Snapshot { room } => format!(
+                "snapshot {}",
+                serde_json::to_string(room).unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_join() {
+        assert_eq!(
+            ClientCommand::parse("join arena alice").unwrap(),
+            ClientCommand::Join {
+                room: "arena".to_string(),
+                name: "alice".to_string(),
+                spectator: false,
+                remote: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_join_as_spectator() {
+        assert_eq!(
+            ClientCommand::parse("join arena alice spectate").unwrap(),
+            ClientCommand::Join {
+                room: "arena".to_string(),
+                name: "alice".to_string(),
+                spectator: true,
+                remote: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_join_with_remote_peer() {
+        assert_eq!(
+            ClientCommand::parse("join arena@127.0.0.1:9091 alice").unwrap(),
+            ClientCommand::Join {
+                room: "arena".to_string(),
+                name: "alice".to_string(),
+                spectator: false,
+                remote: Some("127.0.0.1:9091".parse().unwrap()),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_join_with_invalid_remote_peer() {
+        assert!(ClientCommand::parse("join arena@not-an-addr alice").is_err());
+    }
+
+    #[test]
+    fn parses_attack() {
+        assert_eq!(
+            ClientCommand::parse("attack 5").unwrap(),
+            ClientCommand::Attack { power: 5 }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_attack() {
+        assert!(ClientCommand::parse("attack hard").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_verb() {
+        assert!(ClientCommand::parse("dance").is_err());
+    }
+
+    #[test]
+    fn renders_damaged() {
+        let id = Uuid::nil();
+        let rendered = ServerMessage::Damaged {
+            by: id,
+            amount: 3,
+            hp: 7,
+        }
+        .render();
+        assert_eq!(
+            rendered,
+            format!("damaged by={} amount=3 hp=7", id)
+        );
+    }
+}
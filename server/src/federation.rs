@@ -0,0 +1,313 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::SinkExt;
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::StreamExt;
+use tokio_util::codec::{Framed, LinesCodec};
+use uuid::Uuid;
+
+use crate::commands::{ClientCommand, RoomSnapshot};
+use crate::Shared;
+
+/// Bumped whenever `Message` changes shape; servers with mismatched
+/// versions refuse to link rather than risk desyncing relayed state.
+pub const GAME_VERSION: &str = "1";
+
+/// Once this many peer links (inbound or outbound) are open, `dial` stops
+/// opening new ones; inbound links accepted by `listen` are not capped.
+pub const MAX_PEERS: usize = 16;
+
+/// Shorthand for the transmit half of a peer-link's outgoing message queue.
+type PeerTx = mpsc::UnboundedSender<Message>;
+
+/// `dial` and `run_link` call each other through peer discovery
+/// (`dial_unknown_peers` dials peers learned via `GetPeers`/`Peers`, and a
+/// dialed link can itself learn and dial more peers); boxing the spawned
+/// future here erases that otherwise self-referential type.
+type LinkFuture = Pin<Box<dyn Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// The server-to-server link protocol, exchanged over a dedicated TCP
+/// port separate from the player-facing `LinesCodec` connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Message {
+    Hand {
+        game_version: String,
+        server_name: String,
+    },
+    Shake {
+        ok: bool,
+        room_count: usize,
+    },
+    Ping,
+    Pong,
+    GetPeers,
+    Peers {
+        addrs: Vec<SocketAddr>,
+    },
+    RelayCommand {
+        room: String,
+        player_id: Uuid,
+        cmd: ClientCommand,
+    },
+    RelayState {
+        room: String,
+        snapshot: RoomSnapshot,
+    },
+}
+
+impl Message {
+    pub fn parse(line: &str) -> Result<Message, String> {
+        serde_json::from_str(line).map_err(|e| e.to_string())
+    }
+
+    pub fn render(&self) -> String {
+        serde_json::to_string(self).expect("Message always serializes to JSON")
+    }
+}
+
+/// Tracks the servers this instance links to, independently of the
+/// player-facing `Shared` lock so federation bookkeeping never blocks
+/// gameplay.
+pub struct Federation {
+    pub server_name: String,
+    /// This server's own federation listen address, used as one half of
+    /// the symmetric tie-break `Peer::new` uses to decide which side of a
+    /// freshly federated room moves first (see its doc comment).
+    pub local_addr: SocketAddr,
+    pub known_peers: Mutex<HashSet<SocketAddr>>,
+    links: Mutex<HashMap<SocketAddr, PeerTx>>,
+}
+
+impl Federation {
+    pub fn new(server_name: String, local_addr: SocketAddr) -> Self {
+        Federation {
+            server_name,
+            local_addr,
+            known_peers: Mutex::new(HashSet::new()),
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Sends `message` to the peer at `addr`, if currently linked. This is
+    /// how a room's originating server forwards a `RelayCommand` to the
+    /// server hosting the opponent.
+    pub async fn send_to(&self, addr: SocketAddr, message: Message) {
+        if let Some(tx) = self.links.lock().await.get(&addr) {
+            let _ = tx.send(message);
+        }
+    }
+
+    async fn is_linked(&self, addr: SocketAddr) -> bool {
+        self.links.lock().await.contains_key(&addr)
+    }
+
+    async fn link_count(&self) -> usize {
+        self.links.lock().await.len()
+    }
+}
+
+/// Accepts incoming peer links on `addr`.
+pub async fn listen(
+    addr: SocketAddr,
+    federation: Arc<Federation>,
+    state: Arc<Mutex<Shared>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("federation server running on {}", addr);
+
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let federation = Arc::clone(&federation);
+        let state = Arc::clone(&state);
+
+        tokio::spawn(async move {
+            if let Err(e) = accept_link(stream, peer_addr, federation, state).await {
+                eprintln!("federation link with {} failed; error = {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+/// Dials `addr` and performs the `Hand`/`Shake` handshake as the initiator.
+///
+/// Returns a boxed future (rather than being declared `async fn`) because
+/// `dial` and `run_link` are mutually recursive through peer discovery: a
+/// `Peers` reply dials any addresses it doesn't already know about. Boxing
+/// here gives that cycle a concrete, non-recursive type.
+pub fn dial(addr: SocketAddr, federation: Arc<Federation>, state: Arc<Mutex<Shared>>) -> LinkFuture {
+    Box::pin(async move {
+        if federation.is_linked(addr).await || federation.link_count().await >= MAX_PEERS {
+            return Ok(());
+        }
+
+        let stream = TcpStream::connect(addr).await?;
+        let mut lines = Framed::new(stream, LinesCodec::new());
+
+        lines
+            .send(
+                Message::Hand {
+                    game_version: GAME_VERSION.to_string(),
+                    server_name: federation.server_name.clone(),
+                }
+                .render(),
+            )
+            .await?;
+
+        let shake = match lines.next().await {
+            Some(Ok(line)) => Message::parse(&line)?,
+            _ => return Err("peer closed before completing the handshake".into()),
+        };
+        match shake {
+            Message::Shake { ok: true, .. } => {}
+            Message::Shake { ok: false, .. } => {
+                return Err(format!("peer {} rejected our game_version", addr).into());
+            }
+            other => return Err(format!("expected Shake, got {:?}", other).into()),
+        }
+
+        run_link(lines, addr, federation, state).await
+    })
+}
+
+/// Handles one inbound connection through the handshake before handing
+/// it to `run_link`.
+async fn accept_link(
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    federation: Arc<Federation>,
+    state: Arc<Mutex<Shared>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut lines = Framed::new(stream, LinesCodec::new());
+
+    let hand = match lines.next().await {
+        Some(Ok(line)) => Message::parse(&line)?,
+        _ => return Err("peer closed before sending Hand".into()),
+    };
+    let (remote_version, remote_name) = match hand {
+        Message::Hand {
+            game_version,
+            server_name,
+        } => (game_version, server_name),
+        other => return Err(format!("expected Hand, got {:?}", other).into()),
+    };
+
+    let ok = remote_version == GAME_VERSION;
+    let room_count = state.lock().await.rooms.len();
+    lines.send(Message::Shake { ok, room_count }.render()).await?;
+    if !ok {
+        return Err(format!(
+            "rejected {} ({}): game_version {} != {}",
+            peer_addr, remote_name, remote_version, GAME_VERSION
+        )
+        .into());
+    }
+
+    run_link(lines, peer_addr, federation, state).await
+}
+
+/// Drives one established peer link for its lifetime: registers its
+/// outgoing queue, answers `Ping`/`GetPeers`, dials any peers it learns
+/// about that aren't already linked (bounded by `MAX_PEERS`), and applies
+/// `RelayCommand`/`RelayState` traffic against the shared game state.
+async fn run_link(
+    mut lines: Framed<TcpStream, LinesCodec>,
+    peer_addr: SocketAddr,
+    federation: Arc<Federation>,
+    state: Arc<Mutex<Shared>>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    federation.links.lock().await.insert(peer_addr, tx);
+    federation.known_peers.lock().await.insert(peer_addr);
+
+    let result = async {
+        loop {
+            tokio::select! {
+                outgoing = rx.recv() => match outgoing {
+                    Some(message) => lines.send(message.render()).await?,
+                    None => break,
+                },
+                incoming = lines.next() => match incoming {
+                    Some(Ok(line)) => {
+                        let message = Message::parse(&line)?;
+                        match message {
+                            Message::Ping => lines.send(Message::Pong.render()).await?,
+                            Message::Pong => {}
+                            Message::GetPeers => {
+                                let addrs: Vec<SocketAddr> =
+                                    federation.known_peers.lock().await.iter().copied().collect();
+                                lines.send(Message::Peers { addrs }.render()).await?;
+                            }
+                            Message::Peers { addrs } => {
+                                dial_unknown_peers(
+                                    addrs,
+                                    peer_addr,
+                                    Arc::clone(&federation),
+                                    Arc::clone(&state),
+                                )
+                                .await;
+                            }
+                            Message::RelayCommand { room, player_id, cmd } => {
+                                let snapshot = state
+                                    .lock()
+                                    .await
+                                    .apply_relay_command(&room, player_id, cmd)
+                                    .await;
+                                if let Some(snapshot) = snapshot {
+                                    lines.send(Message::RelayState { room, snapshot }.render()).await?;
+                                }
+                            }
+                            Message::RelayState { room, snapshot } => {
+                                state.lock().await.apply_relay_state(&room, snapshot).await;
+                            }
+                            Message::Hand { .. } | Message::Shake { .. } => {}
+                        }
+                    }
+                    Some(Err(e)) => return Err(Box::new(e) as Box<dyn Error + Send + Sync>),
+                    None => break,
+                },
+            }
+        }
+        Ok(())
+    }
+    .await;
+
+    federation.links.lock().await.remove(&peer_addr);
+    result
+}
+
+/// Records newly learned peer addresses and dials any not already linked,
+/// up to `MAX_PEERS` total links.
+async fn dial_unknown_peers(
+    addrs: Vec<SocketAddr>,
+    from: SocketAddr,
+    federation: Arc<Federation>,
+    state: Arc<Mutex<Shared>>,
+) {
+    {
+        let mut known = federation.known_peers.lock().await;
+        for addr in &addrs {
+            known.insert(*addr);
+        }
+    }
+
+    if federation.link_count().await >= MAX_PEERS {
+        return;
+    }
+
+    for addr in addrs {
+        if addr != from && !federation.is_linked(addr).await {
+            let federation = Arc::clone(&federation);
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                let _ = dial(addr, federation, state).await;
+            });
+        }
+    }
+}
@@ -1,10 +1,13 @@
+mod commands;
+mod federation;
+mod metrics;
+
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Framed, LinesCodec};
 
 use futures::SinkExt;
-use serde_json::Value;
 use std::collections::HashMap;
 use std::env;
 use std::error::Error;
@@ -13,14 +16,35 @@ use std::net::SocketAddr;
 use std::sync::Arc;
 use uuid::Uuid;
 
+use commands::{ClientCommand, PlayerSnapshot, RoomSnapshot, ServerMessage};
+use federation::{Federation, Message as FederationMessage};
+use metrics::Metrics;
+
 /// Shorthand for the transmit half of the message channel.
 type Tx = mpsc::UnboundedSender<String>;
 
 /// Shorthand for the receive half of the message channel.
 type Rx = mpsc::UnboundedReceiver<String>;
 
-struct Shared {
-    rooms: HashMap<String, RoomState>,
+pub(crate) struct Shared {
+    pub(crate) rooms: HashMap<String, RoomState>,
+    metrics: Arc<Metrics>,
+    federation: Arc<Federation>,
+}
+
+/// A player's base defense before any `Defend` bonus is applied.
+const BASE_DEFENSE: i32 = 10;
+
+/// How much defense a `Defend` action adds against the next incoming hit.
+const DEFEND_BONUS: i32 = 5;
+
+/// Applies `power` worth of attack damage to `opponent`, clearing any
+/// `Defend` bonus the hit consumed, and returns the amount dealt.
+fn resolve_damage(opponent: &mut Player, power: i32) -> i32 {
+    let amount = (power - opponent.defense).max(0);
+    opponent.hp -= amount;
+    opponent.defense = BASE_DEFENSE;
+    amount
 }
 
 struct Player {
@@ -30,21 +54,73 @@ struct Player {
     defense: i32,
 }
 
-struct RoomState {
+/// The lifecycle of a single room's match.
+///
+/// Rooms start `WaitingForPlayers`, move to `InProgress` once two players
+/// have joined (`Peer::new`), and settle in `Finished` once a player's hp
+/// reaches zero (`Shared::apply_action`) or a player leaves mid-match
+/// (`Shared::remove_player`). A federated room (`RoomState::remote` is
+/// set) only ever has one local player, so it skips `WaitingForPlayers`
+/// and goes straight to `InProgress` or `WaitingForRemote` on join,
+/// depending on which side moves first; it is also `WaitingForRemote`
+/// from the moment it forwards an `Attack` until the peer that owns the
+/// opponent relays its own attack back.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GameState {
+    WaitingForPlayers,
+    InProgress { turn: Uuid },
+    WaitingForRemote,
+    Finished { winner: Uuid },
+}
+
+/// An action a player can take on their turn, resolved by `apply_action`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum GameAction {
+    Attack { power: i32 },
+    Defend,
+}
+
+/// The result of a successfully resolved `GameAction`, used by `process`
+/// to decide what to broadcast.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ActionOutcome {
+    Damaged {
+        amount: i32,
+        hp: i32,
+        next_turn: Uuid,
+    },
+    Defended {
+        next_turn: Uuid,
+    },
+    GameOver {
+        winner: Uuid,
+    },
+}
+
+pub(crate) struct RoomState {
     peers: HashMap<Uuid, Player>,
-    turn: Option<Uuid>, // Track whose turn it is
+    /// Onlookers that receive every broadcast in the room but never hold
+    /// `turn` and don't count against the 2-player cap.
+    spectators: HashMap<Uuid, Tx>,
+    state: GameState,
+    /// Set when this room's opponent lives on a linked peer server; turns
+    /// taken locally are relayed there instead of applied in-process.
+    remote: Option<SocketAddr>,
 }
 
 struct Peer {
     lines: Framed<TcpStream, LinesCodec>,
     rx: Rx,
     room: String,
+    spectator: bool,
 }
 
 impl Shared {
-    fn new() -> Self {
+    fn new(metrics: Arc<Metrics>, federation: Arc<Federation>) -> Self {
         Shared {
             rooms: HashMap::new(),
+            metrics,
+            federation,
         }
     }
 
@@ -55,61 +131,310 @@ impl Shared {
                     let _ = player.sender.send(message.into());
                 }
             }
+            for (&id, spectator) in &room_state.spectators {
+                if id != user_id {
+                    let _ = spectator.send(message.into());
+                }
+            }
         }
     }
 
-    async fn next_turn(&mut self, room: &str) {
-        if let Some(room_state) = self.rooms.get_mut(room) {
-            let peers: Vec<Uuid> = room_state.peers.keys().copied().collect();
-            room_state.turn = match &room_state.turn {
-                Some(current) => {
-                    let index = peers.iter().position(|&addr| addr == *current).unwrap();
-                    let next_index = (index + 1) % peers.len();
-                    Some(peers[next_index])
+    /// Directly sends `message` to a single player or spectator in `room`,
+    /// used for updates (like `YourTurn`) meant for one recipient rather
+    /// than the whole room.
+    async fn send_to(&self, room: &str, id: Uuid, message: &str) {
+        if let Some(room_state) = self.rooms.get(room) {
+            if let Some(player) = room_state.peers.get(&id) {
+                let _ = player.sender.send(message.into());
+            } else if let Some(spectator) = room_state.spectators.get(&id) {
+                let _ = spectator.send(message.into());
+            }
+        }
+    }
+
+    /// Resolves `action` as taken by `actor_id` in `room`.
+    ///
+    /// Rejects the action if `room` isn't `InProgress` or it isn't
+    /// `actor_id`'s turn. `Attack` is resolved against the other player
+    /// in the room (never the actor), and `Defend` raises the actor's own
+    /// defense against the opponent's next hit. Transitions `room`'s
+    /// `GameState` to `Finished` and bumps `games_completed_total` once an
+    /// action drops a player's hp to zero or below.
+    async fn apply_action(
+        &mut self,
+        room: &str,
+        actor_id: Uuid,
+        action: GameAction,
+    ) -> Result<ActionOutcome, String> {
+        let room_state = self.rooms.get_mut(room).ok_or("no such room")?;
+
+        let turn = match room_state.state {
+            GameState::InProgress { turn } => turn,
+            _ => return Err("the game is not in progress".to_string()),
+        };
+        if turn != actor_id {
+            return Err("it is not your turn".to_string());
+        }
+
+        let opponent_id = room_state
+            .peers
+            .keys()
+            .copied()
+            .find(|&id| id != actor_id)
+            .ok_or("no opponent in room")?;
+
+        match action {
+            GameAction::Attack { power } => {
+                self.metrics.attacks_total.inc();
+                let room_state = self.rooms.get_mut(room).ok_or("no such room")?;
+                let opponent = room_state
+                    .peers
+                    .get_mut(&opponent_id)
+                    .ok_or("no opponent in room")?;
+                let amount = resolve_damage(opponent, power);
+                let hp = opponent.hp;
+
+                if hp <= 0 {
+                    self.metrics.games_completed_total.inc();
+                    room_state.state = GameState::Finished { winner: actor_id };
+                    return Ok(ActionOutcome::GameOver { winner: actor_id });
                 }
-                None => peers.get(0).copied(),
-            };
+
+                room_state.state = GameState::InProgress { turn: opponent_id };
+                Ok(ActionOutcome::Damaged {
+                    amount,
+                    hp,
+                    next_turn: opponent_id,
+                })
+            }
+            GameAction::Defend => {
+                let room_state = self.rooms.get_mut(room).ok_or("no such room")?;
+                let actor = room_state
+                    .peers
+                    .get_mut(&actor_id)
+                    .ok_or("actor left the room")?;
+                actor.defense = BASE_DEFENSE + DEFEND_BONUS;
+                room_state.state = GameState::InProgress { turn: opponent_id };
+                Ok(ActionOutcome::Defended {
+                    next_turn: opponent_id,
+                })
+            }
         }
     }
 
-    // TODO: This is not used correctly, the player ID is the one attacking so the other should be used for applying damage
-    async fn apply_attack(&mut self, room: &str, id: Uuid, damage: i32) -> Option<String> {
-        println!("{}", id);
+    /// Removes `id` from `room`, decrementing the connected-player gauge
+    /// and, once the room is empty, the active-room gauge along with it.
+    ///
+    /// If `id` was a player and the match was still `InProgress` (or
+    /// `WaitingForRemote`), the remaining player wins by forfeit: the room
+    /// transitions to `Finished` and the result is broadcast, so no one is
+    /// left waiting on a turn that can never come. A room with no players
+    /// left at all reverts to `WaitingForPlayers` instead, so a later
+    /// join doesn't walk into a stale `Finished` room.
+    async fn remove_player(&mut self, room: &str, id: Uuid) {
+        let mut forfeit_winner = None;
         if let Some(room_state) = self.rooms.get_mut(room) {
-            if let Some(player) = room_state.peers.get_mut(&id) {
-                let total_damage = player.defense - damage;
-                player.hp -= total_damage;
-                if player.hp <= 0 {
-                    return Some(format!("{} has lost!", player.name));
+            if room_state.peers.remove(&id).is_some() {
+                self.metrics.connected_players.dec();
+                match room_state.state {
+                    GameState::InProgress { .. } | GameState::WaitingForRemote => {
+                        match room_state.peers.keys().next().copied() {
+                            Some(winner) => {
+                                room_state.state = GameState::Finished { winner };
+                                forfeit_winner = Some(winner);
+                            }
+                            None => room_state.state = GameState::WaitingForPlayers,
+                        }
+                    }
+                    GameState::WaitingForPlayers | GameState::Finished { .. } => {}
+                }
+            } else {
+                room_state.spectators.remove(&id);
+            }
+        }
+
+        if let Some(winner) = forfeit_winner {
+            self.metrics.games_completed_total.inc();
+            self.broadcast(room, Uuid::nil(), &ServerMessage::GameOver { winner }.render())
+                .await;
+        }
+
+        if let Some(room_state) = self.rooms.get(room) {
+            if room_state.peers.is_empty() && room_state.spectators.is_empty() {
+                self.rooms.remove(room);
+                self.metrics.active_rooms.dec();
+            }
+        }
+    }
+
+    /// Snapshots both players in `room` for spectators and for the
+    /// `RelayState` sent back to a peer after applying a `RelayCommand`.
+    fn snapshot(&self, room: &str) -> Option<RoomSnapshot> {
+        self.rooms.get(room).map(|room_state| RoomSnapshot {
+            players: room_state
+                .peers
+                .values()
+                .map(|player| PlayerSnapshot {
+                    name: player.name.clone(),
+                    hp: player.hp,
+                })
+                .collect(),
+        })
+    }
+
+    /// Applies a `federation::Message::RelayCommand` against the local
+    /// peer in `room` (the opponent, from the relaying server's point of
+    /// view) and returns the resulting snapshot to send back as
+    /// `RelayState`.
+    ///
+    /// Only applies while the room is `WaitingForRemote` — i.e. it's
+    /// actually the local player's turn to take a hit, not theirs to deal
+    /// one. A relay landing any other time (a duplicate delivery, or a
+    /// stray message racing the local player's own in-flight attack) is
+    /// dropped rather than applied, so neither side can land two hits in a
+    /// row against the turn order `Peer::new`'s first-mover tie-break set up.
+    ///
+    /// If a hit that does apply drops the local player's hp to zero,
+    /// `player_id` (the remote attacker) becomes the room's recorded
+    /// winner and `GameOver` is broadcast locally; otherwise the local
+    /// player's turn is restored, since the peer that just attacked has
+    /// used its turn.
+    async fn apply_relay_command(
+        &mut self,
+        room: &str,
+        player_id: Uuid,
+        cmd: ClientCommand,
+    ) -> Option<RoomSnapshot> {
+        if let ClientCommand::Attack { power } = cmd {
+            let room_state = self.rooms.get_mut(room)?;
+            if room_state.state != GameState::WaitingForRemote {
+                return self.snapshot(room);
+            }
+            self.metrics.attacks_total.inc();
+            let local_id = room_state.peers.keys().next().copied();
+            if let Some(local_id) = local_id {
+                let local = room_state.peers.get_mut(&local_id)?;
+                resolve_damage(local, power);
+                let hp = local.hp;
+                if hp <= 0 {
+                    room_state.state = GameState::Finished { winner: player_id };
+                    self.metrics.games_completed_total.inc();
+                    self.broadcast(
+                        room,
+                        Uuid::nil(),
+                        &ServerMessage::GameOver { winner: player_id }.render(),
+                    )
+                    .await;
+                } else {
+                    room_state.state = GameState::InProgress { turn: local_id };
                 }
             }
         }
-        None
+        self.snapshot(room)
+    }
+
+    /// Applies a `federation::Message::RelayState` received after
+    /// forwarding a `RelayCommand`: fans the remote opponent's updated hp
+    /// out to the local players in `room`, and, if it dropped to zero,
+    /// settles the room as a win for the local player who forwarded the
+    /// attack that caused it.
+    async fn apply_relay_state(&mut self, room: &str, snapshot: RoomSnapshot) {
+        let rendered = serde_json::to_string(&snapshot).unwrap_or_default();
+        self.broadcast(room, Uuid::nil(), &format!("remote_state {}", rendered))
+            .await;
+
+        if !snapshot.players.iter().any(|player| player.hp <= 0) {
+            return;
+        }
+        let Some(room_state) = self.rooms.get_mut(room) else {
+            return;
+        };
+        let Some(winner) = room_state.peers.keys().next().copied() else {
+            return;
+        };
+        room_state.state = GameState::Finished { winner };
+        self.metrics.games_completed_total.inc();
+        self.broadcast(room, Uuid::nil(), &ServerMessage::GameOver { winner }.render())
+            .await;
     }
 }
 
 impl Peer {
+    /// Joins `id` into `room_name`, creating the room if it doesn't exist.
+    ///
+    /// `remote`, set when the client joined as `room@peer-addr`, asks to
+    /// federate the room: its opponent lives on that peer rather than
+    /// locally, so the room only ever holds one local player. That request
+    /// is only honored when it's actually establishing the room — the
+    /// first local player to join an empty, not-yet-federated room; a
+    /// remote-tagged join into a room someone else already occupies can't
+    /// be satisfied either way (see the capacity check below) and must not
+    /// mutate `RoomState::remote`, or it would retroactively shrink that
+    /// room's capacity to 1 and permanently lock out the real second
+    /// player.
+    ///
+    /// A freshly federated room still needs exactly one side to move
+    /// first. Rather than negotiate that over the link, both sides derive
+    /// the same answer from information they already have: whichever
+    /// side's own federation address sorts lower goes first (`InProgress`);
+    /// the other side waits (`WaitingForRemote`) for that player's `Attack`
+    /// to arrive as a `RelayCommand`. Since each side compares the same
+    /// pair of addresses, exactly one of them gets to move.
     async fn new(
         state: Arc<Mutex<Shared>>,
         mut lines: Framed<TcpStream, LinesCodec>,
         room_name: String,
         user_name: String,
         id: Uuid,
+        spectator: bool,
+        remote: Option<SocketAddr>,
     ) -> io::Result<Option<Peer>> {
         let (tx, rx) = mpsc::unbounded_channel();
 
         let mut state = state.lock().await;
+        let metrics = Arc::clone(&state.metrics);
+        let federation = Arc::clone(&state.federation);
+        let is_new_room = !state.rooms.contains_key(&room_name);
         let room_state = state
             .rooms
             .entry(room_name.clone())
             .or_insert_with(|| RoomState {
                 peers: HashMap::new(),
-                turn: None,
+                spectators: HashMap::new(),
+                state: GameState::WaitingForPlayers,
+                remote: None,
             });
+        if is_new_room {
+            metrics.active_rooms.inc();
+        }
 
-        // Check if there are 2 or more people in the room already and prevent the next person from joining
-        if room_state.peers.len() >= 2 {
-            let _ = lines.send("No room in lobby").await;
+        if spectator {
+            room_state.spectators.insert(id, tx);
+            if let Some(snapshot) = state.snapshot(&room_name) {
+                let _ = lines
+                    .send(ServerMessage::Snapshot { room: snapshot }.render())
+                    .await;
+            }
+            return Ok(Some(Peer {
+                lines,
+                rx,
+                room: room_name,
+                spectator: true,
+            }));
+        }
+
+        let establishing_remote =
+            remote.is_some() && room_state.peers.is_empty() && room_state.remote.is_none();
+
+        // A federated room only ever has one local player; a plain room
+        // has two. A remote-tagged join that isn't establishing the room
+        // (it already has a local peer) can't be satisfied as either kind
+        // of room, so it's rejected outright, before touching `remote`.
+        let local_capacity = if room_state.remote.is_some() { 1 } else { 2 };
+        let room_available = remote.is_none() || establishing_remote;
+        if !room_available || room_state.peers.len() >= local_capacity {
+            metrics.rejected_joins_total.inc();
+            let _ = lines.send(ServerMessage::RoomFull.render()).await;
             println!("No room in lobby");
             return Ok(None);
         }
@@ -118,19 +443,29 @@ impl Peer {
             name: user_name,
             sender: tx,
             hp: 10,
-            defense: 10,
+            defense: BASE_DEFENSE,
         };
 
         // Create the player data -> Refactor this. Probably use a UUID instead of the addr
         room_state.peers.insert(id, player_struct);
+        metrics.connected_players.inc();
         if room_state.peers.len() == 2 {
-            room_state.turn = Some(id); // First player to join attacks first
+            room_state.state = GameState::InProgress { turn: id }; // The player completing the pair attacks first
+        } else if establishing_remote {
+            let remote_addr = remote.expect("establishing_remote implies remote.is_some()");
+            room_state.remote = Some(remote_addr);
+            room_state.state = if federation.local_addr < remote_addr {
+                GameState::InProgress { turn: id }
+            } else {
+                GameState::WaitingForRemote
+            };
         }
 
         Ok(Some(Peer {
             lines,
             rx,
             room: room_name,
+            spectator: false,
         }))
     }
 }
@@ -139,29 +474,33 @@ async fn process(
     state: Arc<Mutex<Shared>>,
     stream: TcpStream,
     addr: SocketAddr,
+    mut shutdown_rx: broadcast::Receiver<()>,
 ) -> Result<(), Box<dyn Error>> {
     let mut lines = Framed::new(stream, LinesCodec::new());
 
-    // Ask the user for the room name that they want to join
-    lines.send("Please enter your room name:").await?;
-    let room_name = match lines.next().await {
-        Some(Ok(line)) => line,
-        _ => {
-            eprintln!(
-                "Failed to get room name from {}. Client disconnected.",
-                addr
-            );
-            return Ok(());
-        }
-    };
-
-    // Ask the user for their username
-    lines.send("Please enter your username:").await?;
-    let user_name = match lines.next().await {
-        Some(Ok(user_name)) => user_name,
-        _ => {
-            eprintln!("Failed to get username from {}. Client disconnected.", addr);
-            return Ok(());
+    // The first line must be a `join <room>[@peer-addr] <name> [spectate]` command.
+    lines.send("join <room>[@peer-addr] <name> [spectate]").await?;
+    let (room_name, user_name, spectator, remote) = loop {
+        let line = match lines.next().await {
+            Some(Ok(line)) => line,
+            _ => {
+                eprintln!("Failed to get a join command from {}. Client disconnected.", addr);
+                return Ok(());
+            }
+        };
+        match ClientCommand::parse(&line) {
+            Ok(ClientCommand::Join { room, name, spectator, remote }) => {
+                break (room, name, spectator, remote)
+            }
+            Ok(_) => {
+                let err = ServerMessage::Error {
+                    reason: "you must join a room before doing anything else".to_string(),
+                };
+                lines.send(err.render()).await?;
+            }
+            Err(reason) => {
+                lines.send(ServerMessage::Error { reason }.render()).await?;
+            }
         }
     };
 
@@ -170,38 +509,172 @@ async fn process(
 
     println!("{}", id);
 
-    let peer = Peer::new(state.clone(), lines, room_name.clone(), user_name, id).await?;
+    let peer = Peer::new(
+        state.clone(),
+        lines,
+        room_name.clone(),
+        user_name,
+        id,
+        spectator,
+        remote,
+    )
+    .await?;
     if let Some(mut peer) = peer {
         let mut state_lock = state.lock().await;
         let room_state = state_lock.rooms.get_mut(&room_name).unwrap();
-        if let Some(turn) = room_state.turn {
+        if let GameState::InProgress { turn } = room_state.state {
             if turn == id {
-                let msg = "Your turn";
-                peer.lines.send(msg).await?;
+                peer.lines.send(ServerMessage::YourTurn.render()).await?;
             }
         }
         drop(state_lock); // Release lock immediately after use
 
         loop {
             tokio::select! {
+                _ = shutdown_rx.recv() => {
+                    SinkExt::<String>::send(&mut peer.lines, ServerMessage::ServerShutdown.render()).await?;
+                    SinkExt::<String>::flush(&mut peer.lines).await?;
+                    state.lock().await.remove_player(&peer.room, id).await;
+                    return Ok(());
+                }
                 Some(msg) = peer.rx.recv() => {
                     peer.lines.send(&msg).await?;
+                    // Whoever's turn *didn't* end the game still gets this
+                    // broadcast; stop serving them too so a finished match
+                    // never leaves a connection stuck with no opponent.
+                    if msg.starts_with("game_over ") {
+                        break;
+                    }
                 }
                 result = peer.lines.next() => match result {
-                    Some(Ok(msg)) => {
-                        let mut state = state.lock().await;
-                        println!("{}", &msg);
-                        if msg.contains("attack") {
-                            let value: Value = serde_json::from_str(&msg).unwrap();
-                            println!("{:?}", value);
-                            if let Some(attack_value) = value.get("attack").and_then(|v| v.as_i64()).map(|v| v as i32) {
-                                // TODO: Make this more clear for the players. What should be sent back when an attack has been done?
-                                if let Some(result_message) = state.apply_attack(&peer.room, id, attack_value).await {
-                                    state.broadcast(&peer.room, id, &result_message).await;
-                                    break;
+                    Some(Ok(line)) => {
+                        let command = match ClientCommand::parse(&line) {
+                            Ok(command) => command,
+                            Err(reason) => {
+                                peer.lines.send(ServerMessage::Error { reason }.render()).await?;
+                                continue;
+                            }
+                        };
+
+                        match command {
+                            ClientCommand::Attack { power } => {
+                                if peer.spectator {
+                                    let err = ServerMessage::Error {
+                                        reason: "spectators cannot attack".to_string(),
+                                    };
+                                    peer.lines.send(err.render()).await?;
+                                    continue;
+                                }
+                                let mut state = state.lock().await;
+                                let remote = state.rooms.get(&peer.room).and_then(|r| r.remote);
+                                if let Some(remote_addr) = remote {
+                                    let has_turn = matches!(
+                                        state.rooms.get(&peer.room).map(|r| r.state),
+                                        Some(GameState::InProgress { turn }) if turn == id
+                                    );
+                                    if !has_turn {
+                                        drop(state);
+                                        let err = ServerMessage::Error {
+                                            reason: "it is not your turn".to_string(),
+                                        };
+                                        peer.lines.send(err.render()).await?;
+                                        continue;
+                                    }
+                                    // Mark the turn spent locally until the peer's own
+                                    // retaliating RelayCommand comes back; its RelayState only
+                                    // changes the state when it reports the opponent defeated.
+                                    if let Some(room_state) = state.rooms.get_mut(&peer.room) {
+                                        room_state.state = GameState::WaitingForRemote;
+                                    }
+                                    let federation = Arc::clone(&state.federation);
+                                    drop(state);
+                                    federation
+                                        .send_to(
+                                            remote_addr,
+                                            FederationMessage::RelayCommand {
+                                                room: peer.room.clone(),
+                                                player_id: id,
+                                                cmd: ClientCommand::Attack { power },
+                                            },
+                                        )
+                                        .await;
+                                    continue;
+                                }
+                                match state.apply_action(&peer.room, id, GameAction::Attack { power }).await {
+                                    Ok(ActionOutcome::Damaged { amount, hp, next_turn }) => {
+                                        state
+                                            .broadcast(
+                                                &peer.room,
+                                                Uuid::nil(),
+                                                &ServerMessage::Damaged { by: id, amount, hp }.render(),
+                                            )
+                                            .await;
+                                        state
+                                            .send_to(&peer.room, next_turn, &ServerMessage::YourTurn.render())
+                                            .await;
+                                    }
+                                    Ok(ActionOutcome::GameOver { winner }) => {
+                                        state
+                                            .broadcast(
+                                                &peer.room,
+                                                Uuid::nil(),
+                                                &ServerMessage::GameOver { winner }.render(),
+                                            )
+                                            .await;
+                                        break;
+                                    }
+                                    Ok(ActionOutcome::Defended { .. }) => unreachable!(
+                                        "apply_action never returns Defended for GameAction::Attack"
+                                    ),
+                                    Err(reason) => {
+                                        drop(state);
+                                        peer.lines.send(ServerMessage::Error { reason }.render()).await?;
+                                    }
                                 }
-                                state.next_turn(&peer.room).await;
-                                state.broadcast(&peer.room, id, "next turn").await;
+                            }
+                            ClientCommand::Defend => {
+                                if peer.spectator {
+                                    let err = ServerMessage::Error {
+                                        reason: "spectators cannot act".to_string(),
+                                    };
+                                    peer.lines.send(err.render()).await?;
+                                    continue;
+                                }
+                                let mut state = state.lock().await;
+                                if state.rooms.get(&peer.room).and_then(|r| r.remote).is_some() {
+                                    drop(state);
+                                    let err = ServerMessage::Error {
+                                        reason: "defend is not supported in a federated room yet"
+                                            .to_string(),
+                                    };
+                                    peer.lines.send(err.render()).await?;
+                                    continue;
+                                }
+                                match state.apply_action(&peer.room, id, GameAction::Defend).await {
+                                    Ok(ActionOutcome::Defended { next_turn }) => {
+                                        state
+                                            .send_to(&peer.room, next_turn, &ServerMessage::YourTurn.render())
+                                            .await;
+                                    }
+                                    Ok(_) => unreachable!(
+                                        "apply_action always returns Defended for GameAction::Defend"
+                                    ),
+                                    Err(reason) => {
+                                        drop(state);
+                                        peer.lines.send(ServerMessage::Error { reason }.render()).await?;
+                                    }
+                                }
+                            }
+                            ClientCommand::Say { text } => {
+                                let state = state.lock().await;
+                                state.broadcast(&peer.room, id, &format!("say {}", text)).await;
+                            }
+                            ClientCommand::Quit => break,
+                            ClientCommand::Join { .. } => {
+                                let err = ServerMessage::Error {
+                                    reason: "already joined a room".to_string(),
+                                };
+                                peer.lines.send(err.render()).await?;
                             }
                         }
                     }
@@ -212,11 +685,65 @@ async fn process(
                 },
             }
         }
+
+        state.lock().await.remove_player(&peer.room, id).await;
     }
 
     Ok(())
 }
 
+/// Parsed command-line arguments for the game server.
+struct Args {
+    addr: String,
+    metrics_addr: SocketAddr,
+    federation_addr: SocketAddr,
+    peer_addr: Option<SocketAddr>,
+    server_name: String,
+}
+
+/// Parses `argv[1..]`, consuming each recognized flag's value so it's
+/// never mistaken for the positional bind address (or for another
+/// flag's value). The first token that isn't a recognized flag or a
+/// value it consumed is taken as the bind address.
+fn parse_args(argv: &[String]) -> Result<Args, Box<dyn Error>> {
+    let mut addr = None;
+    let mut metrics_addr = "127.0.0.1:9090".to_string();
+    let mut federation_addr = "127.0.0.1:9091".to_string();
+    let mut peer_addr = None;
+    let mut server_name = None;
+
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--metrics-addr" => {
+                metrics_addr = args.next().ok_or("--metrics-addr requires a value")?.clone()
+            }
+            "--federation-addr" => {
+                federation_addr = args
+                    .next()
+                    .ok_or("--federation-addr requires a value")?
+                    .clone()
+            }
+            "--peer-addr" => {
+                peer_addr = Some(args.next().ok_or("--peer-addr requires a value")?.clone())
+            }
+            "--server-name" => {
+                server_name = Some(args.next().ok_or("--server-name requires a value")?.clone())
+            }
+            _ if addr.is_none() => addr = Some(arg.clone()),
+            other => return Err(format!("unexpected argument {:?}", other).into()),
+        }
+    }
+
+    Ok(Args {
+        addr: addr.unwrap_or_else(|| "127.0.0.1:8080".to_string()),
+        metrics_addr: metrics_addr.parse()?,
+        federation_addr: federation_addr.parse()?,
+        peer_addr: peer_addr.map(|s| s.parse()).transpose()?,
+        server_name: server_name.unwrap_or_else(|| format!("server-{}", Uuid::new_v4())),
+    })
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
@@ -226,24 +753,505 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with_span_events(FmtSpan::FULL)
         .init();
 
-    let state = Arc::new(Mutex::new(Shared::new()));
+    let (shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+    let metrics = Arc::new(Metrics::new());
+
+    let argv: Vec<String> = env::args().collect();
+    let Args {
+        addr,
+        metrics_addr,
+        federation_addr,
+        peer_addr: seed_peer,
+        server_name,
+    } = parse_args(&argv[1..])?;
+
+    let federation = Arc::new(Federation::new(server_name, federation_addr));
+
+    let state = Arc::new(Mutex::new(Shared::new(
+        Arc::clone(&metrics),
+        Arc::clone(&federation),
+    )));
+
+    let ctrl_c_tx = shutdown_tx.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            println!("shutdown signal received");
+            let _ = ctrl_c_tx.send(());
+        }
+    });
+
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_addr, Arc::clone(&metrics)).await {
+            eprintln!("metrics server error; error = {:?}", e);
+        }
+    });
 
-    let addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+    {
+        let federation = Arc::clone(&federation);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = federation::listen(federation_addr, federation, state).await {
+                eprintln!("federation server error; error = {:?}", e);
+            }
+        });
+    }
+    if let Some(peer_addr) = seed_peer {
+        let federation = Arc::clone(&federation);
+        let state = Arc::clone(&state);
+        tokio::spawn(async move {
+            if let Err(e) = federation::dial(peer_addr, federation, state).await {
+                eprintln!("failed to dial seed peer {}; error = {:?}", peer_addr, e);
+            }
+        });
+    }
 
     let listener = TcpListener::bind(&addr).await?;
     println!("server running on {}", addr);
 
+    let mut handles = Vec::new();
+
     loop {
-        let (stream, addr) = listener.accept().await?;
+        tokio::select! {
+            result = listener.accept() => {
+                let (stream, addr) = result?;
 
-        let state = Arc::clone(&state);
+                let state = Arc::clone(&state);
+                let shutdown_rx = shutdown_tx.subscribe();
 
-        tokio::spawn(async move {
-            if let Err(e) = process(state, stream, addr).await {
-                eprintln!("an error occurred; error = {:?}", e);
+                handles.push(tokio::spawn(async move {
+                    if let Err(e) = process(state, stream, addr, shutdown_rx).await {
+                        eprintln!("an error occurred; error = {:?}", e);
+                    }
+                }));
             }
-        });
+            _ = shutdown_rx.recv() => {
+                println!("draining connections before exit");
+                break;
+            }
+        }
+    }
+
+    for handle in handles {
+        let _ = handle.await;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_shared() -> Shared {
+        Shared::new(
+            Arc::new(Metrics::new()),
+            Arc::new(Federation::new(
+                "test".to_string(),
+                "127.0.0.1:9091".parse().unwrap(),
+            )),
+        )
+    }
+
+    /// Inserts a player into `room`, mirroring the turn assignment
+    /// `Peer::new` does when the second player joins: whoever completes
+    /// the pair gets the first turn.
+    fn add_player(shared: &mut Shared, room: &str, id: Uuid, name: &str) {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let room_state = shared
+            .rooms
+            .entry(room.to_string())
+            .or_insert_with(|| RoomState {
+                peers: HashMap::new(),
+                spectators: HashMap::new(),
+                state: GameState::WaitingForPlayers,
+                remote: None,
+            });
+        room_state.peers.insert(
+            id,
+            Player {
+                name: name.to_string(),
+                sender: tx,
+                hp: 10,
+                defense: BASE_DEFENSE,
+            },
+        );
+        if room_state.peers.len() == 2 {
+            room_state.state = GameState::InProgress { turn: id };
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_attack_out_of_turn() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob"); // bob completes the room and holds turn
+
+        let result = shared
+            .apply_action("arena", alice, GameAction::Attack { power: 5 })
+            .await;
+
+        assert_eq!(result, Err("it is not your turn".to_string()));
+    }
+
+    #[tokio::test]
+    async fn attack_damages_the_opponent_not_the_attacker() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob"); // bob holds turn
+
+        let outcome = shared
+            .apply_action("arena", bob, GameAction::Attack { power: 15 })
+            .await
+            .unwrap();
+
+        match outcome {
+            ActionOutcome::Damaged {
+                amount,
+                hp,
+                next_turn,
+            } => {
+                assert_eq!(amount, 15 - BASE_DEFENSE);
+                assert_eq!(hp, 10 - (15 - BASE_DEFENSE));
+                assert_eq!(next_turn, alice);
+            }
+            other => panic!("expected Damaged, got {:?}", other),
+        }
+        assert_eq!(shared.rooms["arena"].peers[&bob].hp, 10, "attacker's own hp must be untouched");
+    }
+
+    #[tokio::test]
+    async fn defend_then_attack_reduces_damage() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob"); // bob holds turn
+
+        shared
+            .apply_action("arena", bob, GameAction::Defend)
+            .await
+            .unwrap();
+
+        let outcome = shared
+            .apply_action("arena", alice, GameAction::Attack { power: 15 })
+            .await
+            .unwrap();
+
+        match outcome {
+            ActionOutcome::Damaged { amount, hp, .. } => {
+                assert_eq!(amount, 15 - (BASE_DEFENSE + DEFEND_BONUS));
+                assert_eq!(hp, 10 - (15 - (BASE_DEFENSE + DEFEND_BONUS)));
+            }
+            other => panic!("expected Damaged, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn attack_ending_the_game_transitions_to_finished() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob"); // bob holds turn
+
+        let outcome = shared
+            .apply_action("arena", bob, GameAction::Attack { power: 100 })
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, ActionOutcome::GameOver { winner: bob });
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::Finished { winner: bob }
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_mid_game_forfeits_to_the_remaining_player() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob"); // InProgress, bob holds turn
+
+        shared.remove_player("arena", alice).await;
+
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::Finished { winner: bob },
+            "the remaining player should win by forfeit rather than being stuck waiting forever"
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_with_no_players_left_clears_the_room() {
+        let mut shared = test_shared();
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+        add_player(&mut shared, "arena", alice, "alice");
+        add_player(&mut shared, "arena", bob, "bob");
+
+        shared.remove_player("arena", alice).await;
+        shared.remove_player("arena", bob).await;
+
+        assert!(!shared.rooms.contains_key("arena"));
+    }
+
+    /// Inserts a federated room with a single local player, mirroring what
+    /// `Peer::new` leaves behind once a `room@peer-addr` join establishes it.
+    fn add_federated_player(
+        shared: &mut Shared,
+        room: &str,
+        id: Uuid,
+        name: &str,
+        remote: SocketAddr,
+        state: GameState,
+    ) {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut peers = HashMap::new();
+        peers.insert(
+            id,
+            Player {
+                name: name.to_string(),
+                sender: tx,
+                hp: 10,
+                defense: BASE_DEFENSE,
+            },
+        );
+        shared.rooms.insert(
+            room.to_string(),
+            RoomState {
+                peers,
+                spectators: HashMap::new(),
+                state,
+                remote: Some(remote),
+            },
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_relay_command_applies_a_hit_while_waiting_for_remote() {
+        let mut shared = test_shared();
+        let local = Uuid::new_v4();
+        let remote_attacker = Uuid::new_v4();
+        add_federated_player(
+            &mut shared,
+            "arena",
+            local,
+            "alice",
+            "127.0.0.1:9091".parse().unwrap(),
+            GameState::WaitingForRemote,
+        );
+
+        let snapshot = shared
+            .apply_relay_command("arena", remote_attacker, ClientCommand::Attack { power: 15 })
+            .await
+            .unwrap();
+
+        assert_eq!(snapshot.players[0].hp, 10 - (15 - BASE_DEFENSE));
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::InProgress { turn: local },
+            "a resolved hit hands the turn back to the local player"
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_relay_command_ignores_a_hit_when_not_expecting_one() {
+        let mut shared = test_shared();
+        let local = Uuid::new_v4();
+        let remote_attacker = Uuid::new_v4();
+        add_federated_player(
+            &mut shared,
+            "arena",
+            local,
+            "alice",
+            "127.0.0.1:9091".parse().unwrap(),
+            GameState::InProgress { turn: local },
+        );
+
+        shared
+            .apply_relay_command("arena", remote_attacker, ClientCommand::Attack { power: 15 })
+            .await;
+
+        assert_eq!(
+            shared.rooms["arena"].peers[&local].hp, 10,
+            "a stray relay landing while it's our own turn must not be applied"
+        );
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::InProgress { turn: local }
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_relay_command_ending_the_game_credits_the_remote_attacker() {
+        let mut shared = test_shared();
+        let local = Uuid::new_v4();
+        let remote_attacker = Uuid::new_v4();
+        add_federated_player(
+            &mut shared,
+            "arena",
+            local,
+            "alice",
+            "127.0.0.1:9091".parse().unwrap(),
+            GameState::WaitingForRemote,
+        );
+
+        shared
+            .apply_relay_command("arena", remote_attacker, ClientCommand::Attack { power: 100 })
+            .await;
+
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::Finished { winner: remote_attacker }
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_relay_state_settles_the_room_when_the_remote_opponent_is_defeated() {
+        let mut shared = test_shared();
+        let local = Uuid::new_v4();
+        add_federated_player(
+            &mut shared,
+            "arena",
+            local,
+            "alice",
+            "127.0.0.1:9091".parse().unwrap(),
+            GameState::WaitingForRemote,
+        );
+
+        shared
+            .apply_relay_state(
+                "arena",
+                RoomSnapshot {
+                    players: vec![PlayerSnapshot {
+                        name: "bob".to_string(),
+                        hp: 0,
+                    }],
+                },
+            )
+            .await;
+
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::Finished { winner: local }
+        );
+    }
+
+    #[tokio::test]
+    async fn apply_relay_state_leaves_the_room_waiting_when_the_remote_opponent_survives() {
+        let mut shared = test_shared();
+        let local = Uuid::new_v4();
+        add_federated_player(
+            &mut shared,
+            "arena",
+            local,
+            "alice",
+            "127.0.0.1:9091".parse().unwrap(),
+            GameState::WaitingForRemote,
+        );
+
+        shared
+            .apply_relay_state(
+                "arena",
+                RoomSnapshot {
+                    players: vec![PlayerSnapshot {
+                        name: "bob".to_string(),
+                        hp: 5,
+                    }],
+                },
+            )
+            .await;
+
+        assert_eq!(
+            shared.rooms["arena"].state,
+            GameState::WaitingForRemote,
+            "a mere hp update shouldn't end the match"
+        );
+    }
+
+    /// A loopback `TcpStream` pair for driving `Peer::new` directly in
+    /// tests; the client half is returned just to keep the connection open.
+    async fn test_stream() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (server, client)
+    }
+
+    #[tokio::test]
+    async fn a_rejected_remote_join_does_not_corrupt_an_existing_rooms_capacity() {
+        let metrics = Arc::new(Metrics::new());
+        let federation = Arc::new(Federation::new(
+            "test".to_string(),
+            "127.0.0.1:9091".parse().unwrap(),
+        ));
+        let state = Arc::new(Mutex::new(Shared::new(
+            Arc::clone(&metrics),
+            Arc::clone(&federation),
+        )));
+
+        let (server, _client) = test_stream().await;
+        let alice = Uuid::new_v4();
+        Peer::new(
+            Arc::clone(&state),
+            Framed::new(server, LinesCodec::new()),
+            "arena".to_string(),
+            "alice".to_string(),
+            alice,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let (server, _client) = test_stream().await;
+        let mallory = Uuid::new_v4();
+        let rejected = Peer::new(
+            Arc::clone(&state),
+            Framed::new(server, LinesCodec::new()),
+            "arena".to_string(),
+            "mallory".to_string(),
+            mallory,
+            false,
+            Some("10.0.0.1:9091".parse().unwrap()),
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            rejected.is_none(),
+            "arena already has a local peer and isn't federated; a remote-tagged join can't be satisfied"
+        );
+        assert_eq!(
+            state.lock().await.rooms["arena"].remote,
+            None,
+            "a rejected join must not retroactively federate (and cap at 1) a room someone else is waiting in"
+        );
+
+        let (server, _client) = test_stream().await;
+        let bob = Uuid::new_v4();
+        let accepted = Peer::new(
+            Arc::clone(&state),
+            Framed::new(server, LinesCodec::new()),
+            "arena".to_string(),
+            "bob".to_string(),
+            bob,
+            false,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert!(
+            accepted.is_some(),
+            "the real second player must still be able to join after the bogus remote join was rejected"
+        );
     }
 }
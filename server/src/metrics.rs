@@ -0,0 +1,98 @@
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Game-server metrics, registered against a single `prometheus::Registry`
+/// and scraped over HTTP by `serve`.
+pub struct Metrics {
+    registry: Registry,
+    pub active_rooms: IntGauge,
+    pub connected_players: IntGauge,
+    pub attacks_total: IntCounter,
+    pub games_completed_total: IntCounter,
+    pub rejected_joins_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let active_rooms = IntGauge::new("active_rooms", "Number of rooms currently open").unwrap();
+        let connected_players =
+            IntGauge::new("connected_players", "Number of players currently connected").unwrap();
+        let attacks_total =
+            IntCounter::new("attacks_total", "Total number of attacks processed").unwrap();
+        let games_completed_total =
+            IntCounter::new("games_completed_total", "Total number of games completed").unwrap();
+        let rejected_joins_total = IntCounter::new(
+            "rejected_joins_total",
+            "Total number of joins rejected because a room was full",
+        )
+        .unwrap();
+
+        registry.register(Box::new(active_rooms.clone())).unwrap();
+        registry
+            .register(Box::new(connected_players.clone()))
+            .unwrap();
+        registry.register(Box::new(attacks_total.clone())).unwrap();
+        registry
+            .register(Box::new(games_completed_total.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(rejected_joins_total.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            active_rooms,
+            connected_players,
+            attacks_total,
+            games_completed_total,
+            rejected_joins_total,
+        }
+    }
+
+    fn gather(&self) -> Vec<u8> {
+        let encoder = TextEncoder::new();
+        let metric_families = self.registry.gather();
+        let mut buffer = Vec::new();
+        encoder.encode(&metric_families, &mut buffer).unwrap();
+        buffer
+    }
+}
+
+/// Serves `metrics` as the text exposition format over HTTP at `/metrics`,
+/// bound to `addr`. Runs until the listener errors.
+pub async fn serve(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = TcpListener::bind(addr).await?;
+    println!("metrics server running on {}", addr);
+
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = Arc::clone(&metrics);
+
+        tokio::spawn(async move {
+            // The scrape endpoint only ever serves GET /metrics, so the
+            // request itself is drained and ignored rather than parsed.
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            let body = metrics.gather();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+
+            if stream.write_all(response.as_bytes()).await.is_ok() {
+                let _ = stream.write_all(&body).await;
+            }
+            let _ = stream.shutdown().await;
+        });
+    }
+}